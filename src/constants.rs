@@ -18,6 +18,50 @@ pub const BL_READ_SECTOR_P_STATUS: u8 = 0x5A;
 pub const BL_OTP_READ: u8 = 0x5B;
 pub const BL_DIS_R_W_PROTECT: u8 = 0x5C;
 
+/// Self-flash recovery: rewrites the bootloader's own sectors (0-1) from a
+/// freshly received image, then resets. Deliberately left out of
+/// `SUPPORTED_COMMANDS` / `BL_GET_HELP` - this is a guarded field-recovery
+/// path, not a normal command a host should stumble into.
+pub const BL_SELF_FLASH_RECOVERY: u8 = 0x5D;
+
+/// Commit the currently-inactive application slot as the one that boots
+/// next. Only takes effect if that slot's image validates, so a host can
+/// never commit a half-written update.
+pub const BL_COMMIT_SLOT: u8 = 0x5E;
+
+/// Renegotiate the USART2 line settings (word length, parity, baud rate).
+/// The ACK is sent at the *old* rate; the new settings only take effect
+/// once the handler has finished transmitting it.
+pub const BL_SET_LINE_CONFIG: u8 = 0x5F;
+
+/// USART2's peripheral clock, fixed by the clock tree configured in `main`
+/// (`pclk1(42.mhz())`). Needed to compute the BRR divisor for a negotiated
+/// baud rate.
+pub const USART2_PCLK_HZ: u32 = 42_000_000;
+
+/// Carries a packetized telecommand (`service::TcPacket`) to the structured
+/// service dispatcher in the `service` module, in place of a one-off
+/// ACK/NACK command.
+pub const BL_SERVICE_REQUEST: u8 = 0x60;
+
+/// Service/subservice identifiers for the `service` module's dispatcher,
+/// modeled loosely on ECSS PUS telecommand/telemetry service numbering.
+pub const SVC_PING: u8 = 1;
+pub const SVC_PING_SUB_PING: u8 = 1;
+
+pub const SVC_MEMORY: u8 = 2;
+pub const SVC_MEMORY_SUB_RAW_WRITE: u8 = 1;
+
+/// Diagnostics service: read-only counters a host can poll to detect link
+/// problems that would otherwise be invisible (e.g. bytes the ISR had to
+/// drop).
+pub const SVC_DIAGNOSTICS: u8 = 3;
+pub const SVC_DIAGNOSTICS_SUB_RX_ERRORS: u8 = 1;
+
+/// Target memory identifiers for the memory service's raw-write action.
+/// Only on-chip flash is supported today.
+pub const MEM_ID_FLASH: u8 = 0;
+
 /// Response codes
 pub const BL_ACK: u8 = 0xA5;
 pub const BL_NACK: u8 = 0x7F;
@@ -53,6 +97,39 @@ pub const BKPSRAM_END: u32 = BKPSRAM_BASE + BKPSRAM_SIZE;
 /// User application flash sector
 pub const FLASH_SECTOR2_BASE_ADDRESS: u32 = 0x08008000;
 
+/// Dual-slot application layout
+///
+/// Slot A starts at the legacy application sector, so images linked for that
+/// address need no relinking, but a slot only boots once its metadata below
+/// has been written and validates; Slot B sits directly above it. The last 8 bytes of
+/// each slot are reserved for image metadata: a little-endian length word
+/// followed by a little-endian CRC32 word, both written by the flashing
+/// tool after a successful transfer.
+pub const APP_SLOT_A_BASE: u32 = FLASH_SECTOR2_BASE_ADDRESS;
+pub const APP_SLOT_SIZE: u32 = 64 * 1024;
+pub const APP_SLOT_B_BASE: u32 = APP_SLOT_A_BASE + APP_SLOT_SIZE;
+
+/// Offset of the stored image length word within a slot
+pub const APP_SLOT_LEN_OFFSET: u32 = APP_SLOT_SIZE - 8;
+/// Offset of the stored image CRC32 word within a slot
+pub const APP_SLOT_CRC_OFFSET: u32 = APP_SLOT_SIZE - 4;
+
+/// Persistent "which slot boots next" selector, one byte (0 = slot A, 1 =
+/// slot B, anything else defaults to slot A). Lives in the battery-backed
+/// Backup SRAM so it survives a reset or power loss, not just flash
+/// validity - this is what makes commit/rollback meaningful.
+pub const BOOT_SELECTOR_ADDR: u32 = BKPSRAM_BASE;
+
+/// One-time-programmable (OTP) memory on STM32F407xx: 512 bytes organized as
+/// 16 blocks of 32 bytes, plus 16 lock bytes (one per block).
+pub const OTP_BASE: u32 = 0x1FFF_7800;
+pub const OTP_SIZE: u32 = 512;
+pub const OTP_BLOCK_SIZE: u32 = 32;
+pub const OTP_NUM_BLOCKS: u8 = 16;
+
+pub const OTP_LOCK_BASE: u32 = 0x1FFF_7A00;
+pub const OTP_LOCK_SIZE: u32 = 16;
+
 /// Maximum receive buffer length
 pub const BL_RX_LEN: usize = 200;
 
@@ -70,4 +147,7 @@ pub const SUPPORTED_COMMANDS: &[u8] = &[
     BL_READ_SECTOR_P_STATUS,
     BL_OTP_READ,
     BL_DIS_R_W_PROTECT,
+    BL_COMMIT_SLOT,
+    BL_SET_LINE_CONFIG,
+    BL_SERVICE_REQUEST,
 ];
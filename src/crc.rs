@@ -6,9 +6,74 @@
 const CRC_INIT: u32 = 0xFFFF_FFFF;
 const CRC_POLY: u32 = 0x04C11DB7;
 
-/// Verify the CRC of a full protocol frame.
+/// A CRC32 engine matching the frame protocol's polynomial (0x04C11DB7) and
+/// initial value (0xFFFFFFFF).
+///
+/// Abstracting over the engine lets the bootloader offload frame checking to
+/// the STM32's hardware CRC unit while keeping a software fallback for
+/// targets (or tests) where that peripheral isn't available.
+pub trait CrcEngine {
+    /// Compute the CRC32 over `data`, starting from a fresh CRC_INIT state.
+    fn compute(&mut self, data: &[u8]) -> u32;
+}
+
+/// Software CRC engine: the original byte-fed 32-bit update model.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SoftwareCrc;
+
+impl CrcEngine for SoftwareCrc {
+    fn compute(&mut self, data: &[u8]) -> u32 {
+        calculate_crc(data)
+    }
+}
+
+/// Hardware CRC engine backed by the STM32F4 CRC peripheral.
+///
+/// The peripheral is hardwired to the same polynomial and initial value this
+/// module implements in software (one 32-bit `CRC->DR` write per byte,
+/// zero-extended, matching the software engine's per-byte update), but that
+/// wire-compatibility can only be exercised on target - the peripheral isn't
+/// available to the host test harness, so this type has no `cfg(test)`
+/// implementation and isn't constructed by tests in this module.
+pub struct HardwareCrc {
+    #[cfg(not(test))]
+    crc: stm32f4xx_hal::stm32::CRC,
+}
+
+impl HardwareCrc {
+    #[cfg(not(test))]
+    pub fn new(crc: stm32f4xx_hal::stm32::CRC) -> Self {
+        // The CRC unit is unclocked out of reset (RCC.AHB1ENR.CRCEN).
+        unsafe {
+            (*stm32f4xx_hal::stm32::RCC::ptr()).ahb1enr.modify(|_, w| w.crcen().set_bit());
+        }
+        HardwareCrc { crc }
+    }
+
+    #[cfg(not(test))]
+    fn reset(&mut self) {
+        self.crc.cr.write(|w| w.reset().set_bit());
+    }
+}
+
+#[cfg(not(test))]
+impl CrcEngine for HardwareCrc {
+    fn compute(&mut self, data: &[u8]) -> u32 {
+        self.reset();
+
+        for &byte in data {
+            unsafe {
+                self.crc.dr.write(|w| w.bits(byte as u32));
+            }
+        }
+
+        self.crc.dr.read().bits()
+    }
+}
+
+/// Verify the CRC of a full protocol frame using the given engine.
 /// Returns false for malformed frames.
-pub fn verify_frame_crc(frame: &[u8]) -> bool {
+pub fn verify_frame_crc(frame: &[u8], engine: &mut dyn CrcEngine) -> bool {
     if frame.len() < 6 {
         return false;
     }
@@ -21,7 +86,7 @@ pub fn verify_frame_crc(frame: &[u8]) -> bool {
         frame[data_len + 3],
     ]);
 
-    calculate_crc(&frame[..data_len]) == expected
+    engine.compute(&frame[..data_len]) == expected
 }
 
 /// Calculate CRC over a byte slice using the same byte-fed 32-bit update model
@@ -49,3 +114,51 @@ fn accumulate_word_crc(mut crc: u32, data: u32) -> u32 {
 
     crc
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `HardwareCrc` has no `CrcEngine` implementation under `cfg(test)` (the
+    // real CRC peripheral isn't available on the host), so the two engines
+    // can't be compared against each other here. These instead check
+    // `calculate_crc`'s byte-feeding against known-answer values computed
+    // independently from the documented algorithm (CRC_INIT = 0xFFFFFFFF,
+    // poly = 0x04C11DB7, each byte zero-extended to 32 bits before the
+    // update - matching how a byte write to the real `CRC->DR` is
+    // zero-extended internally).
+
+    #[test]
+    fn calculate_crc_of_empty_input_is_the_initial_value() {
+        assert_eq!(calculate_crc(&[]), 0xFFFF_FFFF);
+    }
+
+    #[test]
+    fn calculate_crc_matches_known_answer_for_single_byte() {
+        assert_eq!(calculate_crc(&[0x41]), 0xF743_B0BB);
+    }
+
+    #[test]
+    fn calculate_crc_matches_known_answer_for_sample_frame() {
+        let frame = [0x09u8, 0x57, 0xDE, 0xAD, 0xBE, 0xEF, 0x01, 0x02, 0x03];
+        assert_eq!(calculate_crc(&frame), 0x296C_2731);
+    }
+
+    #[test]
+    fn software_engine_matches_known_answer_via_compute() {
+        let payload = [0x05u8, 0x51, 0xAA];
+        let mut sw = SoftwareCrc;
+        assert_eq!(sw.compute(&payload), 0x2C6D_9B01);
+    }
+
+    #[test]
+    fn verify_frame_crc_accepts_a_frame_built_with_the_known_answer() {
+        let payload = [0x05u8, 0x51, 0xAA];
+        let crc = 0x2C6D_9B01u32;
+        let mut frame = payload.to_vec();
+        frame.extend_from_slice(&crc.to_le_bytes());
+
+        let mut sw = SoftwareCrc;
+        assert!(verify_frame_crc(&frame, &mut sw));
+    }
+}
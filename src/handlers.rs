@@ -1,8 +1,16 @@
 // Bootloader command handlers
 use crate::constants::*;
-use crate::uart::UartComm;
+use crate::uart::{LineConfig, Parity, Transport, TxHalf};
 use crate::memory;
-use embedded_hal::serial::Write;
+use crate::memory::MemoryAccess;
+use crate::flash;
+use stm32f4xx_hal::flash::Flash;
+
+/// Shared state threaded through command handlers that need access to
+/// on-chip peripherals beyond the UART, such as the flash controller.
+pub struct CommandContext<'a> {
+    pub flash: &'a mut Flash,
+}
 
 pub trait CommandHandler {
     fn handle_get_version(&self);
@@ -25,155 +33,303 @@ pub fn get_bootloader_version() -> u8 {
 }
 
 /// Handle BL_GET_VER command
-pub fn handle_getver_cmd<W: Write<u8>>(_packet: &[u8], _uart: &mut UartComm, serial: &mut W) {
+pub fn handle_getver_cmd(_packet: &[u8], tx: &TxHalf, serial: &mut impl Transport) {
     let version = get_bootloader_version();
-    
-    UartComm::send_ack(BL_GET_VER, 1, serial);
-    UartComm::write_byte(version, serial);
+
+    tx.send_ack(BL_GET_VER, 1, serial);
+    tx.write_byte(version, serial);
 }
 
 /// Handle BL_GET_HELP command
-pub fn handle_gethelp_cmd<W: Write<u8>>(_uart: &mut UartComm, serial: &mut W) {
+pub fn handle_gethelp_cmd(tx: &TxHalf, serial: &mut impl Transport) {
     let num_commands = SUPPORTED_COMMANDS.len() as u8;
-    
-    UartComm::send_ack(BL_GET_HELP, num_commands, serial);
-    UartComm::write_buffer(SUPPORTED_COMMANDS, serial);
+
+    tx.send_ack(BL_GET_HELP, num_commands, serial);
+    tx.write_buffer(SUPPORTED_COMMANDS, serial);
 }
 
 /// Handle BL_GET_CID command
-pub fn handle_getcid_cmd<W: Write<u8>>(_uart: &mut UartComm, serial: &mut W) {
+pub fn handle_getcid_cmd(tx: &TxHalf, serial: &mut impl Transport) {
     let chip_id = memory::get_mcu_chip_id();
     let cid_bytes = chip_id.to_le_bytes();
-    
-    UartComm::send_ack(BL_GET_CID, 2, serial);
-    UartComm::write_buffer(&cid_bytes, serial);
+
+    tx.send_ack(BL_GET_CID, 2, serial);
+    tx.write_buffer(&cid_bytes, serial);
 }
 
 /// Handle BL_GET_RDP_STATUS command
-pub fn handle_getrdp_cmd<W: Write<u8>>(_uart: &mut UartComm, serial: &mut W) {
+pub fn handle_getrdp_cmd(tx: &TxHalf, serial: &mut impl Transport) {
     let rdp_level = memory::get_flash_rdp_level();
-    
-    UartComm::send_ack(BL_GET_RDP_STATUS, 1, serial);
-    UartComm::write_byte(rdp_level, serial);
+
+    tx.send_ack(BL_GET_RDP_STATUS, 1, serial);
+    tx.write_byte(rdp_level, serial);
 }
 
 /// Handle BL_GO_TO_ADDR command
-pub fn handle_go_cmd<W: Write<u8>>(packet: &[u8], _uart: &mut UartComm, serial: &mut W) {
+pub fn handle_go_cmd(packet: &[u8], tx: &TxHalf, serial: &mut impl Transport) {
     if packet.len() < 4 {
-        UartComm::send_nack(serial);
+        tx.send_nack(serial);
         return;
     }
-    
+
     let address = u32::from_le_bytes([packet[0], packet[1], packet[2], packet[3]]);
-    
+
     if memory::verify_address(address) == ADDR_VALID {
-        UartComm::send_ack(BL_GO_TO_ADDR, 0, serial);
-        
+        tx.send_ack(BL_GO_TO_ADDR, 0, serial);
+
         // Small delay to ensure ACK is transmitted
         cortex_m::asm::delay(1000);
-        
+
         // Jump to address
         jump_to_address(address);
     } else {
-        UartComm::send_nack(serial);
+        tx.send_nack(serial);
     }
 }
 
 /// Handle BL_FLASH_ERASE command
-pub fn handle_flash_erase_cmd<W: Write<u8>>(packet: &[u8], _uart: &mut UartComm, serial: &mut W) {
+pub fn handle_flash_erase_cmd(
+    packet: &[u8],
+    ctx: &mut CommandContext,
+    tx: &TxHalf,
+    serial: &mut impl Transport,
+) {
     if packet.len() < 2 {
-        UartComm::send_nack(serial);
+        tx.send_nack(serial);
         return;
     }
-    
-    let _sector_number = packet[0];
-    let _number_of_sectors = packet[1];
 
-    // Command currently not wired to flash controller in main context.
-    UartComm::send_nack(serial);
+    let sector_number = packet[0];
+    let number_of_sectors = packet[1];
+
+    match flash::execute_flash_erase(ctx.flash, sector_number, number_of_sectors) {
+        Ok(()) => tx.send_ack(BL_FLASH_ERASE, 0, serial),
+        Err(_) => tx.send_nack(serial),
+    }
 }
 
 /// Handle BL_MEM_WRITE command
-pub fn handle_mem_write_cmd<W: Write<u8>>(packet: &[u8], _uart: &mut UartComm, serial: &mut W) {
+pub fn handle_mem_write_cmd(
+    packet: &[u8],
+    ctx: &mut CommandContext,
+    tx: &TxHalf,
+    serial: &mut impl Transport,
+) {
     if packet.len() < 6 {
-        UartComm::send_nack(serial);
+        tx.send_nack(serial);
         return;
     }
-    
+
     let address = u32::from_le_bytes([packet[0], packet[1], packet[2], packet[3]]);
     let write_len = packet[4];
-    
+
     if (5 + write_len as usize) > packet.len() {
-        UartComm::send_nack(serial);
+        tx.send_nack(serial);
         return;
     }
-    
-    let _data = &packet[5..5 + write_len as usize];
-    
-    // Verify address first, but this command is currently not wired to flash controller.
-    if memory::verify_address(address) == ADDR_VALID {
-        UartComm::send_nack(serial);
-    } else {
-        UartComm::send_nack(serial);
+
+    let data = &packet[5..5 + write_len as usize];
+
+    if memory::verify_address(address) != ADDR_VALID || !memory::is_write_target_allowed(address) {
+        tx.send_nack(serial);
+        return;
+    }
+
+    match flash::execute_mem_write(ctx.flash, address, data) {
+        Ok(()) => tx.send_ack(BL_MEM_WRITE, 0, serial),
+        Err(_) => tx.send_nack(serial),
     }
 }
 
 /// Handle BL_MEM_READ command
-pub fn handle_mem_read_cmd<W: Write<u8>>(packet: &[u8], _uart: &mut UartComm, serial: &mut W) {
+pub fn handle_mem_read_cmd(
+    packet: &[u8],
+    mem: &impl MemoryAccess,
+    tx: &TxHalf,
+    serial: &mut impl Transport,
+) {
     if packet.len() < 6 {
-        UartComm::send_nack(serial);
+        tx.send_nack(serial);
         return;
     }
-    
+
     let address = u32::from_le_bytes([packet[0], packet[1], packet[2], packet[3]]);
     let read_len = packet[4];
-    
+
     if memory::verify_address(address) == ADDR_VALID {
-        UartComm::send_ack(BL_MEM_READ, read_len, serial);
-        
+        tx.send_ack(BL_MEM_READ, read_len, serial);
+
         // Read and send data
         for i in 0..read_len {
-            let addr = (address + i as u32) as *const u8;
-            let byte = unsafe { core::ptr::read_volatile(addr) };
-            UartComm::write_byte(byte, serial);
+            let byte = mem.read_byte(address + i as u32);
+            tx.write_byte(byte, serial);
         }
     } else {
-        UartComm::send_nack(serial);
+        tx.send_nack(serial);
     }
 }
 
 /// Handle BL_EN_RW_PROTECT command
-pub fn handle_en_rw_protect_cmd<W: Write<u8>>(packet: &[u8], _uart: &mut UartComm, serial: &mut W) {
+pub fn handle_en_rw_protect_cmd(packet: &[u8], tx: &TxHalf, serial: &mut impl Transport) {
     if packet.len() < 2 {
-        UartComm::send_nack(serial);
+        tx.send_nack(serial);
         return;
     }
-    
-    UartComm::send_nack(serial);
+
+    let sector_details = packet[0];
+    let protection_mode = packet[1];
+
+    match flash::configure_flash_sector_rw_protection(sector_details, protection_mode, false) {
+        Ok(()) => tx.send_ack(BL_EN_RW_PROTECT, 0, serial),
+        Err(_) => tx.send_nack(serial),
+    }
 }
 
 /// Handle BL_DIS_R_W_PROTECT command
-pub fn handle_dis_rw_protect_cmd<W: Write<u8>>(_packet: &[u8], _uart: &mut UartComm, serial: &mut W) {
-    UartComm::send_nack(serial);
+pub fn handle_dis_rw_protect_cmd(_packet: &[u8], tx: &TxHalf, serial: &mut impl Transport) {
+    match flash::configure_flash_sector_rw_protection(0, 0, true) {
+        Ok(()) => tx.send_ack(BL_DIS_R_W_PROTECT, 0, serial),
+        Err(_) => tx.send_nack(serial),
+    }
 }
 
 /// Handle BL_READ_SECTOR_P_STATUS command
-pub fn handle_read_sector_protection_cmd<W: Write<u8>>(_uart: &mut UartComm, serial: &mut W) {
+pub fn handle_read_sector_protection_cmd(tx: &TxHalf, serial: &mut impl Transport) {
     let protection_status = crate::flash::read_ob_rw_protection_status();
     let status_bytes = protection_status.to_le_bytes();
-    
-    UartComm::send_ack(BL_READ_SECTOR_P_STATUS, 2, serial);
-    UartComm::write_buffer(&status_bytes, serial);
+
+    tx.send_ack(BL_READ_SECTOR_P_STATUS, 2, serial);
+    tx.write_buffer(&status_bytes, serial);
 }
 
 /// Handle BL_OTP_READ command
-pub fn handle_read_otp_cmd<W: Write<u8>>(_uart: &mut UartComm, serial: &mut W) {
-    // OTP read - stub for now
-    UartComm::send_nack(serial);
+pub fn handle_read_otp_cmd(packet: &[u8], tx: &TxHalf, serial: &mut impl Transport) {
+    if packet.len() < 3 {
+        tx.send_nack(serial);
+        return;
+    }
+
+    let block = packet[0];
+    let offset = packet[1];
+    let len = packet[2];
+
+    let mut buf = [0u8; u8::MAX as usize];
+    if memory::read_otp(block, offset, len, &mut buf[..len as usize]) {
+        tx.send_ack(BL_OTP_READ, len, serial);
+        tx.write_buffer(&buf[..len as usize], serial);
+    } else {
+        tx.send_nack(serial);
+    }
+}
+
+/// Handle BL_SELF_FLASH_RECOVERY command
+///
+/// Rewrites the bootloader's own flash sectors with the received image and
+/// resets into it. See `recovery::flash_self_from_ram` for why this has to
+/// run from RAM. Only reachable via the dedicated command code - it is not
+/// advertised in `SUPPORTED_COMMANDS`.
+pub fn handle_self_flash_recovery_cmd(
+    packet: &[u8],
+    ctx: &mut CommandContext,
+    tx: &TxHalf,
+    serial: &mut impl Transport,
+) {
+    if packet.len() < 2 {
+        tx.send_nack(serial);
+        return;
+    }
+
+    let image_len = u16::from_le_bytes([packet[0], packet[1]]) as usize;
+    if packet.len() < 2 + image_len {
+        tx.send_nack(serial);
+        return;
+    }
+
+    let image = &packet[2..2 + image_len];
+
+    tx.send_ack(BL_SELF_FLASH_RECOVERY, 0, serial);
+
+    // On success this resets the MCU and never returns.
+    if crate::recovery::flash_self_from_ram(ctx.flash, image).is_err() {
+        tx.send_nack(serial);
+    }
+}
+
+/// Handle BL_COMMIT_SLOT command
+///
+/// Commits the currently-inactive slot as the one that boots next. Refuses
+/// (NACKs) if that slot's image doesn't validate, so a host can never
+/// commit a half-written update and strand the device.
+pub fn handle_commit_slot_cmd(tx: &TxHalf, serial: &mut impl Transport) {
+    let candidate = memory::inactive_slot_base();
+
+    if memory::validate_app_image(candidate) {
+        memory::commit_inactive_slot();
+        tx.send_ack(BL_COMMIT_SLOT, 0, serial);
+    } else {
+        tx.send_nack(serial);
+    }
+}
+
+/// Handle BL_SET_LINE_CONFIG command
+///
+/// Payload: [word_length_bits][parity: 0=none/1=even/2=odd][baud_rate LE u32].
+/// ACKs at the *old* line settings, then reprograms USART2 for the new ones -
+/// a host must switch its own baud rate immediately after seeing the ACK.
+pub fn handle_set_line_config_cmd(packet: &[u8], tx: &TxHalf, serial: &mut impl Transport) {
+    if packet.len() < 6 {
+        tx.send_nack(serial);
+        return;
+    }
+
+    let parity = match packet[1] {
+        0 => Parity::None,
+        1 => Parity::Even,
+        2 => Parity::Odd,
+        _ => {
+            tx.send_nack(serial);
+            return;
+        }
+    };
+
+    let config = LineConfig {
+        word_length_bits: packet[0],
+        parity,
+        baud_rate: u32::from_le_bytes([packet[2], packet[3], packet[4], packet[5]]),
+    };
+
+    if !config.is_valid() || config.baud_rate == 0 {
+        tx.send_nack(serial);
+        return;
+    }
+
+    tx.send_ack(BL_SET_LINE_CONFIG, 0, serial);
+
+    let usart2 = unsafe { &*stm32f4xx_hal::stm32::USART2::ptr() };
+    crate::uart::apply_line_config(usart2, USART2_PCLK_HZ, &config);
+}
+
+/// Handle BL_SERVICE_REQUEST command
+///
+/// Parses `packet` as a `service::TcPacket` and routes it through
+/// `service::dispatch`, which answers with typed acceptance/start/completion
+/// TM reports instead of a single ACK/NACK. A packet too short to be a valid
+/// TC header is NACKed at the framing level, same as any other malformed
+/// command.
+pub fn handle_service_request_cmd(
+    packet: &[u8],
+    ctx: &mut CommandContext,
+    tx: &TxHalf,
+    serial: &mut impl Transport,
+) {
+    match crate::service::TcPacket::parse(packet) {
+        Some(tc) => crate::service::dispatch(&tc, ctx, tx, serial),
+        None => tx.send_nack(serial),
+    }
 }
 
 /// Jump to application code
-/// 
+///
 /// This function:
 /// 1. Sets the MSP (Main Stack Pointer) from the app reset vector
 /// 2. Jumps to the app reset handler
@@ -183,12 +339,106 @@ fn jump_to_address(address: u32) -> ! {
         // Configure MSP from app base address
         let msp = core::ptr::read_volatile(address as *const u32);
         cortex_m::register::msp::write(msp);
-        
+
         // Fetch reset handler address (at address + 4)
         let reset_handler = core::ptr::read_volatile((address + 4) as *const u32);
-        
+
         // Create function pointer and jump
         let jump: extern "C" fn() -> ! = core::mem::transmute(reset_handler);
         jump();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MockMemory;
+    use crate::uart::MockTransport;
+
+    #[test]
+    fn mem_read_happy_path_streams_requested_bytes() {
+        let mem = MockMemory::new(FLASH_BASE, std::vec![0x11, 0x22, 0x33, 0x44]);
+        let tx = TxHalf::new();
+        let mut transport = MockTransport::default();
+
+        // [address LE][read_len] - the frame's length/CRC fields aren't part
+        // of the handler's payload; CommandPacket::parse strips them upstream.
+        let packet = FLASH_BASE.to_le_bytes();
+        let payload = [packet[0], packet[1], packet[2], packet[3], 4];
+
+        handle_mem_read_cmd(&payload, &mem, &tx, &mut transport);
+
+        assert_eq!(
+            transport.written,
+            std::vec![BL_ACK, BL_MEM_READ, 4, 0x11, 0x22, 0x33, 0x44]
+        );
+    }
+
+    #[test]
+    fn mem_read_rejects_invalid_address() {
+        let mem = MockMemory::new(FLASH_BASE, std::vec![0u8; 4]);
+        let tx = TxHalf::new();
+        let mut transport = MockTransport::default();
+
+        // An address well outside any valid region.
+        let payload = [0xFF, 0xFF, 0xFF, 0xFF, 4];
+
+        handle_mem_read_cmd(&payload, &mem, &tx, &mut transport);
+
+        assert_eq!(transport.written, std::vec![BL_NACK]);
+    }
+
+    #[test]
+    fn mem_read_nacks_short_packet() {
+        let mem = MockMemory::new(FLASH_BASE, std::vec![0u8; 4]);
+        let tx = TxHalf::new();
+        let mut transport = MockTransport::default();
+
+        handle_mem_read_cmd(&[0, 0], &mem, &tx, &mut transport);
+
+        assert_eq!(transport.written, std::vec![BL_NACK]);
+    }
+
+    #[test]
+    fn otp_read_nacks_out_of_range_block() {
+        let tx = TxHalf::new();
+        let mut transport = MockTransport::default();
+
+        handle_read_otp_cmd(&[OTP_NUM_BLOCKS, 0, 4], &tx, &mut transport);
+
+        assert_eq!(transport.written, std::vec![BL_NACK]);
+    }
+
+    #[test]
+    fn set_line_config_nacks_9bit_with_parity() {
+        let tx = TxHalf::new();
+        let mut transport = MockTransport::default();
+
+        let baud = 9600u32.to_le_bytes();
+        let payload = [9, 1, baud[0], baud[1], baud[2], baud[3]];
+
+        handle_set_line_config_cmd(&payload, &tx, &mut transport);
+
+        assert_eq!(transport.written, std::vec![BL_NACK]);
+    }
+
+    #[test]
+    fn set_line_config_nacks_short_packet() {
+        let tx = TxHalf::new();
+        let mut transport = MockTransport::default();
+
+        handle_set_line_config_cmd(&[8, 0], &tx, &mut transport);
+
+        assert_eq!(transport.written, std::vec![BL_NACK]);
+    }
+
+    #[test]
+    fn otp_read_nacks_short_packet() {
+        let tx = TxHalf::new();
+        let mut transport = MockTransport::default();
+
+        handle_read_otp_cmd(&[0, 0], &tx, &mut transport);
+
+        assert_eq!(transport.written, std::vec![BL_NACK]);
+    }
+}
@@ -1,10 +1,11 @@
 // ararext Bootloader for STM32F407xx
 // A high-performance bootloader implementation in Rust
 
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 
 use cortex_m_rt::entry;
+#[cfg(not(test))]
 use panic_halt as _;
 use stm32f4xx_hal::prelude::*;
 use stm32f4xx_hal::serial::config::Config;
@@ -14,19 +15,35 @@ mod constants;
 mod crc;
 mod flash;
 mod memory;
+mod recovery;
+mod rx_irq;
+mod service;
 mod uart;
 mod handlers;
 
 use constants::*;
 use uart::{UartComm, CommandPacket};
 use handlers::*;
+use stm32f4xx_hal::flash::Flash;
 
 /// System initialization and main bootloader loop
 #[entry]
 fn main() -> ! {
     // Get peripherals
     let dp = stm32::Peripherals::take().unwrap();
-    
+
+    // BOOT_SELECTOR_ADDR (the A/B slot selector) lives in Backup SRAM, which
+    // is unclocked and write-protected out of reset - this has to run before
+    // anything touches it, including `select_boot_slot` below.
+    memory::enable_backup_domain();
+
+    // Unlocked flash controller, shared by the command handlers that erase
+    // or program the device (BL_FLASH_ERASE, BL_MEM_WRITE, ...).
+    let mut flash = Flash::new(dp.FLASH);
+
+    // Frame CRC32 is offloaded to the STM32F407's hardware CRC unit.
+    let mut crc_engine = crc::HardwareCrc::new(dp.CRC);
+
     // Setup clocks
     let rcc = dp.RCC.constrain();
     let clocks = rcc.cfgr
@@ -50,15 +67,27 @@ fn main() -> ! {
     // TX: PA2, RX: PA3
     let tx = gpioa.pa2.into_alternate_af7();
     let rx = gpioa.pa3.into_alternate_af7();
+
+    // Enable interrupt-driven RX before handing USART2 to the (TX-only, from
+    // here on) blocking Serial driver.
+    let mut rx_irq = rx_irq::RxWithIrq::new(&dp.USART2);
+
+    // RxWithIrq::new only arms RXNEIE/IDLEIE/EIE in the peripheral; the
+    // interrupt still has to be unmasked at the NVIC or `#[interrupt] fn
+    // USART2` never fires and the ring buffer never fills.
+    unsafe {
+        cortex_m::peripheral::NVIC::unmask(stm32::Interrupt::USART2);
+    }
+
     let serial = stm32f4xx_hal::serial::Serial::usart2(
         dp.USART2,
         (tx, rx),
         Config::default().baudrate(115_200.bps()),
         clocks,
     ).unwrap();
-    
-    let (mut tx, mut rx) = serial.split();
-    
+
+    let (mut tx, _rx) = serial.split();
+
     // Setup USART3 (Debug output UART)
     // TX: PB10, RX: PB11
     let tx_debug = gpiob.pb10.into_alternate_af7();
@@ -82,126 +111,153 @@ fn main() -> ! {
     if button.is_low().unwrap_or(false) {
         // Button pressed - enter bootloader mode
         led.set_high();
-        bootloader_loop(&mut rx, &mut tx);
+        bootloader_loop(&mut rx_irq, &mut tx, &mut flash, &mut crc_engine);
     } else {
-        // Button not pressed - jump to user application
-        led.set_low();
-        jump_to_user_app();
+        // Button not pressed - boot the first validated application slot
+        match select_boot_slot() {
+            Some(base) => {
+                led.set_low();
+                jump_to_app_at(base);
+            }
+            None => {
+                // Neither slot has a valid image - stay in bootloader mode and
+                // signal the fault with a fast blink before falling through to
+                // the normal bootloader entry sequence.
+                for _ in 0..6 {
+                    led.set_high();
+                    cortex_m::asm::delay(2_100_000);
+                    led.set_low();
+                    cortex_m::asm::delay(2_100_000);
+                }
+                led.set_high();
+                bootloader_loop(&mut rx_irq, &mut tx, &mut flash, &mut crc_engine);
+            }
+        }
     }
 }
 
+/// Pick the slot to boot: the committed active slot if it still validates,
+/// falling back to the other slot so a corrupted active image doesn't brick
+/// the device while a good image sits in the other slot.
+fn select_boot_slot() -> Option<u32> {
+    let active = memory::active_slot_base();
+    let inactive = memory::inactive_slot_base();
+
+    for base in [active, inactive] {
+        if memory::validate_app_image(base) {
+            return Some(base);
+        }
+    }
+    None
+}
+
 /// Main bootloader command loop
 fn bootloader_loop(
-    rx: &mut stm32f4xx_hal::serial::Rx<stm32::USART2>,
+    rx: &mut rx_irq::RxWithIrq,
     tx: &mut stm32f4xx_hal::serial::Tx<stm32::USART2>,
+    flash: &mut Flash,
+    crc_engine: &mut dyn crc::CrcEngine,
 ) -> ! {
-    use embedded_hal::serial::Read;
-    
-    let mut uart = UartComm::new();
-    
-    'boot: loop {
-        // Read command length (first byte)
-        let length = match nb::block!(rx.read()) {
-            Ok(byte) => byte,
-            Err(_) => {
-                UartComm::send_nack(tx);
-                continue;
-            }
-        };
+    let (uart_tx, _uart_rx) = UartComm::new().split();
+    let mut ctx = CommandContext { flash };
 
-        // Frame length includes the first length byte.
-        let frame_len = (length as usize) + 1;
-        if frame_len > BL_RX_LEN || length < 5 {
-            UartComm::send_nack(tx);
-            continue;
-        }
-        
-        // Read command packet
-        let mut buffer = [0u8; BL_RX_LEN];
-        buffer[0] = length;
-        
-        for i in 1..frame_len {
-            match nb::block!(rx.read()) {
-                Ok(byte) => buffer[i] = byte,
-                Err(_) => {
-                    UartComm::send_nack(tx);
+    'boot: loop {
+        // Poll the ISR-fed ring buffer for a complete COBS frame; this never
+        // blocks the way a direct `nb::block!` read on the UART would.
+        let mut frame_buf = [0u8; BL_RX_LEN];
+        let frame_len = loop {
+            if let Some(decoded) = rx.try_read_frame() {
+                if decoded.len() < 5 {
+                    uart_tx.send_nack(tx);
                     continue 'boot;
                 }
+                frame_buf[..decoded.len()].copy_from_slice(decoded);
+                break decoded.len();
             }
-        }
+        };
 
-        let frame = &buffer[..frame_len];
+        let frame = &frame_buf[..frame_len];
 
-        if !crc::verify_frame_crc(frame) {
-            UartComm::send_nack(tx);
+        if !crc::verify_frame_crc(frame, crc_engine) {
+            uart_tx.send_nack(tx);
             continue;
         }
-        
+
         // Parse command packet
         if let Some(packet) = CommandPacket::parse(frame) {
             match packet.command {
                 BL_GET_VER => {
-                    handle_getver_cmd(&packet.payload[..packet.payload_len], &mut uart, tx);
+                    handle_getver_cmd(&packet.payload[..packet.payload_len], &uart_tx, tx);
                 }
                 BL_GET_HELP => {
-                    handle_gethelp_cmd(&mut uart, tx);
+                    handle_gethelp_cmd(&uart_tx, tx);
                 }
                 BL_GET_CID => {
-                    handle_getcid_cmd(&mut uart, tx);
+                    handle_getcid_cmd(&uart_tx, tx);
                 }
                 BL_GET_RDP_STATUS => {
-                    handle_getrdp_cmd(&mut uart, tx);
+                    handle_getrdp_cmd(&uart_tx, tx);
                 }
                 BL_GO_TO_ADDR => {
-                    handle_go_cmd(&packet.payload[..packet.payload_len], &mut uart, tx);
+                    handle_go_cmd(&packet.payload[..packet.payload_len], &uart_tx, tx);
                 }
                 BL_FLASH_ERASE => {
-                    handle_flash_erase_cmd(&packet.payload[..packet.payload_len], &mut uart, tx);
+                    handle_flash_erase_cmd(&packet.payload[..packet.payload_len], &mut ctx, &uart_tx, tx);
                 }
                 BL_MEM_WRITE => {
-                    handle_mem_write_cmd(&packet.payload[..packet.payload_len], &mut uart, tx);
+                    handle_mem_write_cmd(&packet.payload[..packet.payload_len], &mut ctx, &uart_tx, tx);
                 }
                 BL_EN_RW_PROTECT => {
-                    handle_en_rw_protect_cmd(&packet.payload[..packet.payload_len], &mut uart, tx);
+                    handle_en_rw_protect_cmd(&packet.payload[..packet.payload_len], &uart_tx, tx);
                 }
                 BL_MEM_READ => {
-                    handle_mem_read_cmd(&packet.payload[..packet.payload_len], &mut uart, tx);
+                    handle_mem_read_cmd(&packet.payload[..packet.payload_len], &memory::McuMemory, &uart_tx, tx);
                 }
                 BL_READ_SECTOR_P_STATUS => {
-                    handle_read_sector_protection_cmd(&mut uart, tx);
+                    handle_read_sector_protection_cmd(&uart_tx, tx);
                 }
                 BL_OTP_READ => {
-                    handle_read_otp_cmd(&mut uart, tx);
+                    handle_read_otp_cmd(&packet.payload[..packet.payload_len], &uart_tx, tx);
                 }
                 BL_DIS_R_W_PROTECT => {
-                    handle_dis_rw_protect_cmd(&packet.payload[..packet.payload_len], &mut uart, tx);
+                    handle_dis_rw_protect_cmd(&packet.payload[..packet.payload_len], &uart_tx, tx);
+                }
+                BL_SELF_FLASH_RECOVERY => {
+                    handle_self_flash_recovery_cmd(&packet.payload[..packet.payload_len], &mut ctx, &uart_tx, tx);
+                }
+                BL_COMMIT_SLOT => {
+                    handle_commit_slot_cmd(&uart_tx, tx);
+                }
+                BL_SET_LINE_CONFIG => {
+                    handle_set_line_config_cmd(&packet.payload[..packet.payload_len], &uart_tx, tx);
+                }
+                BL_SERVICE_REQUEST => {
+                    handle_service_request_cmd(&packet.payload[..packet.payload_len], &mut ctx, &uart_tx, tx);
                 }
                 _ => {
                     // Unknown command
-                    UartComm::send_nack(tx);
+                    uart_tx.send_nack(tx);
                 }
             }
         } else {
-            UartComm::send_nack(tx);
+            uart_tx.send_nack(tx);
         }
     }
 }
 
-/// Jump to user application
-/// 
-/// This function assumes the user application is located at FLASH_SECTOR2_BASE_ADDRESS.
-/// It configures the MSP and jumps to the reset handler.
-fn jump_to_user_app() -> ! {
+/// Jump to the application image in the given boot slot
+///
+/// This function configures the MSP from the slot's reset vector and jumps
+/// to the slot's reset handler.
+fn jump_to_app_at(base: u32) -> ! {
     unsafe {
-        // Configure MSP from app reset vector at FLASH_SECTOR2_BASE_ADDRESS
-        let msp = core::ptr::read_volatile(FLASH_SECTOR2_BASE_ADDRESS as *const u32);
+        // Configure MSP from the slot's reset vector
+        let msp = core::ptr::read_volatile(base as *const u32);
         cortex_m::register::msp::write(msp);
-        
-        // Fetch reset handler address (at FLASH_SECTOR2_BASE_ADDRESS + 4)
-        let reset_handler = core::ptr::read_volatile(
-            (FLASH_SECTOR2_BASE_ADDRESS + 4) as *const u32
-        );
-        
+
+        // Fetch reset handler address (at base + 4)
+        let reset_handler = core::ptr::read_volatile((base + 4) as *const u32);
+
         // Jump to reset handler
         let jump: extern "C" fn() -> ! = core::mem::transmute(reset_handler);
         jump()
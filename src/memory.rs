@@ -1,6 +1,59 @@
 // Memory and address validation module
 use crate::constants::*;
 
+const RCC_BASE: u32 = 0x4002_3800;
+const RCC_AHB1ENR: u32 = RCC_BASE + 0x30;
+const RCC_APB1ENR: u32 = RCC_BASE + 0x40;
+const RCC_AHB1ENR_BKPSRAMEN: u32 = 1 << 18;
+const RCC_APB1ENR_PWREN: u32 = 1 << 28;
+
+const PWR_BASE: u32 = 0x4000_7000;
+const PWR_CR: u32 = PWR_BASE;
+const PWR_CR_DBP: u32 = 1 << 8;
+
+/// Enable the Backup SRAM clock and backup-domain write access.
+///
+/// `BOOT_SELECTOR_ADDR` lives in Backup SRAM, which stays unclocked until
+/// `RCC.AHB1ENR.BKPSRAMEN` is set and (like the rest of the backup domain)
+/// ignores writes until `PWR.CR.DBP` is set - without both, reads return
+/// undefined data and writes are silently dropped. Must run once, before
+/// the first `active_slot_base`/`inactive_slot_base`/`commit_inactive_slot`
+/// call.
+pub fn enable_backup_domain() {
+    unsafe {
+        let apb1enr = core::ptr::read_volatile(RCC_APB1ENR as *const u32);
+        core::ptr::write_volatile(RCC_APB1ENR as *mut u32, apb1enr | RCC_APB1ENR_PWREN);
+
+        let pwr_cr = core::ptr::read_volatile(PWR_CR as *const u32);
+        core::ptr::write_volatile(PWR_CR as *mut u32, pwr_cr | PWR_CR_DBP);
+
+        let ahb1enr = core::ptr::read_volatile(RCC_AHB1ENR as *const u32);
+        core::ptr::write_volatile(RCC_AHB1ENR as *mut u32, ahb1enr | RCC_AHB1ENR_BKPSRAMEN);
+    }
+}
+
+/// Abstraction over raw memory reads so command handlers (e.g. MEM_READ) can
+/// be exercised off-target against a plain byte buffer instead of real
+/// `core::ptr::read_volatile` hardware access.
+pub trait MemoryAccess {
+    fn read_byte(&self, address: u32) -> u8;
+    fn read_u32(&self, address: u32) -> u32;
+}
+
+/// `MemoryAccess` implementation backed by real volatile reads, used on target.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct McuMemory;
+
+impl MemoryAccess for McuMemory {
+    fn read_byte(&self, address: u32) -> u8 {
+        unsafe { core::ptr::read_volatile(address as *const u8) }
+    }
+
+    fn read_u32(&self, address: u32) -> u32 {
+        unsafe { core::ptr::read_volatile(address as *const u32) }
+    }
+}
+
 /// Verify if an address is valid for jumping
 /// 
 /// Valid regions:
@@ -71,3 +124,214 @@ pub fn identify_memory_region(address: u32) -> MemoryRegion {
         _ => MemoryRegion::Unknown,
     }
 }
+
+/// Validate the application image stored in a dual-slot boot slot
+///
+/// Reads the length word at `base + APP_SLOT_LEN_OFFSET`, runs the frame CRC32
+/// over exactly that many bytes starting at `base`, and compares it against
+/// the CRC word at `base + APP_SLOT_CRC_OFFSET`. A slot with a zero or
+/// out-of-range length is treated as empty/invalid rather than read further.
+pub fn validate_app_image(base: u32) -> bool {
+    let stored_len = unsafe { core::ptr::read_volatile((base + APP_SLOT_LEN_OFFSET) as *const u32) };
+    let stored_crc = unsafe { core::ptr::read_volatile((base + APP_SLOT_CRC_OFFSET) as *const u32) };
+
+    if stored_len == 0 || stored_len > APP_SLOT_LEN_OFFSET {
+        return false;
+    }
+
+    let image = unsafe { core::slice::from_raw_parts(base as *const u8, stored_len as usize) };
+    crate::crc::calculate_crc(image) == stored_crc
+}
+
+/// Read `len` bytes from OTP `block` starting at `offset` into `buf`.
+///
+/// Bounds-checks `block` against `OTP_NUM_BLOCKS` and `offset + len` against
+/// `OTP_BLOCK_SIZE` before touching memory. Returns `false` (leaving `buf`
+/// untouched) on an out-of-range request.
+pub fn read_otp(block: u8, offset: u8, len: u8, buf: &mut [u8]) -> bool {
+    if block >= OTP_NUM_BLOCKS {
+        return false;
+    }
+
+    if (offset as u32 + len as u32) > OTP_BLOCK_SIZE {
+        return false;
+    }
+
+    if (len as usize) > buf.len() {
+        return false;
+    }
+
+    let base = OTP_BASE + (block as u32) * OTP_BLOCK_SIZE + offset as u32;
+    for i in 0..len as u32 {
+        buf[i as usize] = unsafe { core::ptr::read_volatile((base + i) as *const u8) };
+    }
+
+    true
+}
+
+/// Which slot (`APP_SLOT_A_BASE` or `APP_SLOT_B_BASE`) boots next.
+///
+/// Backed by `BOOT_SELECTOR_ADDR` in Backup SRAM, so it survives a reset
+/// independently of which slot's image happens to validate - the slot a
+/// flash tool has committed to stays "active" until it commits the other.
+pub fn active_slot_base() -> u32 {
+    let raw = unsafe { core::ptr::read_volatile(BOOT_SELECTOR_ADDR as *const u8) };
+    if raw == 1 {
+        APP_SLOT_B_BASE
+    } else {
+        APP_SLOT_A_BASE
+    }
+}
+
+/// The slot that is *not* currently set to boot next - flash-write commands
+/// target this slot so an interrupted update can never touch the slot the
+/// device would otherwise boot into.
+pub fn inactive_slot_base() -> u32 {
+    if active_slot_base() == APP_SLOT_A_BASE {
+        APP_SLOT_B_BASE
+    } else {
+        APP_SLOT_A_BASE
+    }
+}
+
+/// Flip the active slot to whichever one is currently inactive.
+///
+/// Callers must validate the inactive slot's image (`validate_app_image`)
+/// before calling this, so a host can never commit a half-written update.
+pub fn commit_inactive_slot() {
+    let next = if active_slot_base() == APP_SLOT_A_BASE { 1u8 } else { 0u8 };
+    unsafe {
+        core::ptr::write_volatile(BOOT_SELECTOR_ADDR as *mut u8, next);
+    }
+}
+
+/// Whether `address` is an allowed target for a flash-write command.
+///
+/// Addresses inside either application slot are only allowed within the
+/// currently-inactive slot, so MEM_WRITE can never corrupt the slot the
+/// device would boot into next. Addresses outside both slots (e.g. OTP,
+/// option bytes) are unaffected by this rule.
+pub fn is_write_target_allowed(address: u32) -> bool {
+    let region_start = APP_SLOT_A_BASE.min(APP_SLOT_B_BASE);
+    let region_end = APP_SLOT_A_BASE.max(APP_SLOT_B_BASE) + APP_SLOT_SIZE;
+
+    if address >= region_start && address < region_end {
+        let inactive = inactive_slot_base();
+        address >= inactive && address < inactive + APP_SLOT_SIZE
+    } else {
+        true
+    }
+}
+
+/// `MemoryAccess` implementation backed by a plain byte array, used in tests.
+#[cfg(test)]
+pub struct MockMemory {
+    pub base: u32,
+    pub data: std::vec::Vec<u8>,
+}
+
+#[cfg(test)]
+impl MockMemory {
+    pub fn new(base: u32, data: std::vec::Vec<u8>) -> Self {
+        MockMemory { base, data }
+    }
+}
+
+#[cfg(test)]
+impl MemoryAccess for MockMemory {
+    fn read_byte(&self, address: u32) -> u8 {
+        self.data[(address - self.base) as usize]
+    }
+
+    fn read_u32(&self, address: u32) -> u32 {
+        let offset = (address - self.base) as usize;
+        u32::from_le_bytes([
+            self.data[offset],
+            self.data[offset + 1],
+            self.data[offset + 2],
+            self.data[offset + 3],
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sram1_region_boundaries() {
+        assert_eq!(verify_address(SRAM1_BASE), ADDR_VALID);
+        assert_eq!(verify_address(SRAM1_END), ADDR_VALID);
+        assert_eq!(verify_address(SRAM1_BASE - 1), ADDR_INVALID);
+        assert!(matches!(identify_memory_region(SRAM1_BASE), MemoryRegion::SRAM1));
+    }
+
+    #[test]
+    fn sram2_region_boundaries() {
+        assert_eq!(verify_address(SRAM2_BASE), ADDR_VALID);
+        assert_eq!(verify_address(SRAM2_END), ADDR_VALID);
+        assert_eq!(verify_address(SRAM2_END + 1), ADDR_INVALID);
+        assert!(matches!(identify_memory_region(SRAM2_END), MemoryRegion::SRAM2));
+    }
+
+    #[test]
+    fn flash_region_boundaries() {
+        assert_eq!(verify_address(FLASH_BASE), ADDR_VALID);
+        assert_eq!(verify_address(FLASH_END), ADDR_VALID);
+        assert_eq!(verify_address(FLASH_END + 1), ADDR_INVALID);
+        assert!(matches!(identify_memory_region(FLASH_BASE), MemoryRegion::Flash));
+    }
+
+    #[test]
+    fn backup_sram_region_boundaries() {
+        assert_eq!(verify_address(BKPSRAM_BASE), ADDR_VALID);
+        assert_eq!(verify_address(BKPSRAM_END), ADDR_VALID);
+        assert_eq!(verify_address(BKPSRAM_END + 1), ADDR_INVALID);
+        assert!(matches!(identify_memory_region(BKPSRAM_BASE), MemoryRegion::BackupSram));
+    }
+
+    #[test]
+    fn gap_between_regions_is_unknown_and_invalid() {
+        let gap_address = SRAM2_END + 1;
+        assert_eq!(verify_address(gap_address), ADDR_INVALID);
+        assert!(matches!(identify_memory_region(gap_address), MemoryRegion::Unknown));
+    }
+
+    #[test]
+    fn read_otp_rejects_out_of_range_block() {
+        let mut buf = [0u8; 4];
+        assert!(!read_otp(OTP_NUM_BLOCKS, 0, 4, &mut buf));
+    }
+
+    #[test]
+    fn read_otp_rejects_offset_len_past_block_end() {
+        let mut buf = [0u8; 4];
+        assert!(!read_otp(0, OTP_BLOCK_SIZE as u8 - 2, 4, &mut buf));
+    }
+
+    #[test]
+    fn read_otp_rejects_buffer_smaller_than_len() {
+        let mut buf = [0u8; 2];
+        assert!(!read_otp(0, 0, 4, &mut buf));
+    }
+
+    #[test]
+    fn write_target_allowed_outside_app_slots() {
+        assert!(is_write_target_allowed(OTP_BASE));
+    }
+
+    #[test]
+    fn write_target_restricted_to_inactive_slot() {
+        let inactive = inactive_slot_base();
+        let active = active_slot_base();
+        assert!(is_write_target_allowed(inactive));
+        assert!(!is_write_target_allowed(active));
+    }
+
+    #[test]
+    fn mock_memory_reads_match_backing_buffer() {
+        let mock = MockMemory::new(FLASH_BASE, std::vec![0xDE, 0xAD, 0xBE, 0xEF]);
+        assert_eq!(mock.read_byte(FLASH_BASE), 0xDE);
+        assert_eq!(mock.read_u32(FLASH_BASE), 0xEFBEADDE);
+    }
+}
@@ -0,0 +1,140 @@
+// Self-flash recovery: rewrites the bootloader's own sectors from RAM
+//
+// Flash cannot be erased or programmed while code is executing out of the
+// same bank, so the *entire* erase+program sequence that touches sectors
+// 0-1 - not just its outer wrapper - is relocated into SRAM (`.data`)
+// before it runs, and the MCU is reset once the new bootloader image is in
+// place. It deliberately bypasses `flash::execute_flash_erase` /
+// `execute_mem_write`: those call into `stm32f4xx_hal::flash::Flash`'s
+// `erase`/`program`, which live in ordinary `.text` (flash) and would stall
+// or fault mid-erase, so the raw FLASH peripheral register sequence is
+// reimplemented here instead.
+use crate::constants::*;
+use stm32f4xx_hal::flash::Flash;
+
+/// Number of low sectors that make up the bootloader image itself.
+pub const BOOTLOADER_SECTOR_COUNT: u8 = 2;
+
+const FLASH_REG_BASE: u32 = 0x4002_3C00;
+const FLASH_KEYR: u32 = FLASH_REG_BASE + 0x04;
+const FLASH_SR: u32 = FLASH_REG_BASE + 0x0C;
+const FLASH_CR: u32 = FLASH_REG_BASE + 0x10;
+
+const FLASH_KEY1: u32 = 0x4567_0123;
+const FLASH_KEY2: u32 = 0xCDEF_89AB;
+
+const CR_PG: u32 = 1 << 0;
+const CR_SER: u32 = 1 << 1;
+const CR_SNB_SHIFT: u32 = 3;
+const CR_SNB_MASK: u32 = 0x1F << CR_SNB_SHIFT;
+const CR_PSIZE_X8: u32 = 0b00 << 8;
+const CR_PSIZE_MASK: u32 = 0x3 << 8;
+const CR_STRT: u32 = 1 << 16;
+const CR_LOCK: u32 = 1 << 31;
+const SR_BSY: u32 = 1 << 16;
+
+const SCB_AIRCR: u32 = 0xE000_ED0C;
+const AIRCR_VECTKEY: u32 = 0x05FA << 16;
+const AIRCR_PRIGROUP_MASK: u32 = 0x7 << 8;
+const AIRCR_SYSRESETREQ: u32 = 1 << 2;
+
+/// Request a system reset via a direct `SCB->AIRCR` write instead of
+/// `cortex_m::peripheral::SCB::sys_reset()`, whose code lives in ordinary
+/// `.text` - in flash sector 0/1, the very region this function just
+/// reprogrammed. Calling it here would fetch whatever instructions the new
+/// image happens to have at that address instead of the real reset routine.
+/// The write itself doesn't take effect for a few cycles, so this spins in
+/// place (not a call to any other function) until the reset lands.
+#[link_section = ".data"]
+#[inline(never)]
+unsafe fn ram_system_reset() -> ! {
+    let current = core::ptr::read_volatile(SCB_AIRCR as *const u32);
+    core::ptr::write_volatile(
+        SCB_AIRCR as *mut u32,
+        AIRCR_VECTKEY | (current & AIRCR_PRIGROUP_MASK) | AIRCR_SYSRESETREQ,
+    );
+
+    loop {}
+}
+
+/// Spin on the busy flag. Called between every register step of the erase
+/// and program sequences below, so it has to live in RAM alongside them.
+#[link_section = ".data"]
+#[inline(never)]
+unsafe fn ram_wait_while_busy() {
+    while core::ptr::read_volatile(FLASH_SR as *const u32) & SR_BSY != 0 {}
+}
+
+/// Unlock the flash control register via the KEYR sequence, if not already
+/// unlocked.
+#[link_section = ".data"]
+#[inline(never)]
+unsafe fn ram_unlock() {
+    if core::ptr::read_volatile(FLASH_CR as *const u32) & CR_LOCK != 0 {
+        core::ptr::write_volatile(FLASH_KEYR as *mut u32, FLASH_KEY1);
+        core::ptr::write_volatile(FLASH_KEYR as *mut u32, FLASH_KEY2);
+    }
+}
+
+/// Erase one sector via direct CR register manipulation (SNB/SER/STRT).
+#[link_section = ".data"]
+#[inline(never)]
+unsafe fn ram_erase_sector(sector: u8) {
+    ram_wait_while_busy();
+
+    let mut cr = core::ptr::read_volatile(FLASH_CR as *const u32);
+    cr = (cr & !CR_SNB_MASK) | ((sector as u32) << CR_SNB_SHIFT) | CR_SER;
+    core::ptr::write_volatile(FLASH_CR as *mut u32, cr);
+    core::ptr::write_volatile(FLASH_CR as *mut u32, cr | CR_STRT);
+
+    ram_wait_while_busy();
+    core::ptr::write_volatile(FLASH_CR as *mut u32, cr & !CR_SER);
+}
+
+/// Program one byte via direct CR/PG register manipulation.
+#[link_section = ".data"]
+#[inline(never)]
+unsafe fn ram_program_byte(address: u32, byte: u8) {
+    ram_wait_while_busy();
+
+    let cr = (core::ptr::read_volatile(FLASH_CR as *const u32) & !CR_PSIZE_MASK) | CR_PSIZE_X8 | CR_PG;
+    core::ptr::write_volatile(FLASH_CR as *mut u32, cr);
+    core::ptr::write_volatile(address as *mut u8, byte);
+
+    ram_wait_while_busy();
+    core::ptr::write_volatile(FLASH_CR as *mut u32, cr & !CR_PG);
+}
+
+/// Erase and reprogram the bootloader's own sectors (0-1) with `image`, then
+/// reset the MCU.
+///
+/// The whole erase+program sequence - unlock, per-sector erase, per-byte
+/// program - is relocated into SRAM via `#[link_section = ".data"]` because
+/// it erases the very sector it would otherwise be executing from; nothing
+/// it calls may live in flash. On success it never returns - the MCU resets
+/// into the new bootloader. It only returns to the caller on failure,
+/// before anything has been erased.
+#[link_section = ".data"]
+#[inline(never)]
+pub fn flash_self_from_ram(_flash: &mut Flash, image: &[u8]) -> Result<(), &'static str> {
+    let bootloader_region_size = BOOTLOADER_SECTOR_COUNT as u32 * 16 * 1024;
+    if image.len() as u32 > bootloader_region_size {
+        return Err("Image too large for bootloader sectors");
+    }
+
+    unsafe {
+        ram_unlock();
+
+        for sector in 0..BOOTLOADER_SECTOR_COUNT {
+            ram_erase_sector(sector);
+        }
+
+        for (offset, &byte) in image.iter().enumerate() {
+            ram_program_byte(FLASH_BASE + offset as u32, byte);
+        }
+
+        core::ptr::write_volatile(FLASH_CR as *mut u32, CR_LOCK);
+
+        ram_system_reset()
+    }
+}
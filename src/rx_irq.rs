@@ -0,0 +1,244 @@
+// Interrupt-driven UART2 RX with a ring buffer
+//
+// `RxHalf::read_frame` blocks on `nb::block!`, which stalls the whole
+// bootloader on the UART and makes timeouts impossible. This module
+// decouples the ISR producer - pushing each received byte into a
+// fixed-capacity ring buffer - from a non-blocking consumer polled from the
+// main loop.
+use crate::constants::BL_RX_LEN;
+use crate::uart::cobs_decode;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use stm32f4xx_hal::stm32::{interrupt, USART2};
+
+/// Ring buffer capacity; must be a power of two. Generous headroom over one
+/// max-length COBS-encoded frame so a burst doesn't overrun before the main
+/// loop drains it.
+const RING_CAPACITY: usize = 256;
+const RING_MASK: usize = RING_CAPACITY - 1;
+
+/// Lock-free single-producer single-consumer byte ring buffer.
+///
+/// Sound as long as exactly one caller ever calls `push` (the ISR) and
+/// exactly one caller ever calls `pop` (the main-loop consumer): each side
+/// only ever writes its own index and reads the other's with
+/// Acquire/Release ordering.
+struct RingBuffer {
+    buf: UnsafeCell<[u8; RING_CAPACITY]>,
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+unsafe impl Sync for RingBuffer {}
+
+impl RingBuffer {
+    const fn new() -> Self {
+        RingBuffer {
+            buf: UnsafeCell::new([0; RING_CAPACITY]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Push a byte; returns `false` (dropping the byte) if the buffer is full.
+    fn push(&self, byte: u8) -> bool {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+
+        if head.wrapping_sub(tail) >= RING_CAPACITY {
+            return false;
+        }
+
+        unsafe {
+            (*self.buf.get())[head & RING_MASK] = byte;
+        }
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    /// Pop the oldest queued byte, if any.
+    fn pop(&self) -> Option<u8> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+
+        if tail == head {
+            return None;
+        }
+
+        let byte = unsafe { (*self.buf.get())[tail & RING_MASK] };
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Some(byte)
+    }
+
+    fn len(&self) -> usize {
+        let head = self.head.load(Ordering::Acquire);
+        let tail = self.tail.load(Ordering::Acquire);
+        head.wrapping_sub(tail)
+    }
+}
+
+static RX_RING: RingBuffer = RingBuffer::new();
+static OVERRUN_COUNT: AtomicU32 = AtomicU32::new(0);
+static FRAMING_ERROR_COUNT: AtomicU32 = AtomicU32::new(0);
+
+/// Bytes dropped due to a ring-buffer overrun or a hardware USART overrun.
+///
+/// Free function over the same counter `RxWithIrq::overrun_count` reads, so
+/// the service dispatcher (which doesn't own the `RxWithIrq` instance) can
+/// report it to the host without needing `&mut` access to RX state.
+pub fn overrun_count() -> u32 {
+    OVERRUN_COUNT.load(Ordering::Relaxed)
+}
+
+/// USART framing errors reported by the peripheral. See `overrun_count`.
+pub fn framing_error_count() -> u32 {
+    FRAMING_ERROR_COUNT.load(Ordering::Relaxed)
+}
+
+/// Interrupt-driven RX half of USART2.
+///
+/// `poll()`/`try_read_frame()` are non-blocking: they only ever drain bytes
+/// the ISR has already queued, so the main loop never stalls waiting on the
+/// UART.
+pub struct RxWithIrq {
+    encoded: [u8; BL_RX_LEN],
+    encoded_len: usize,
+    decoded: [u8; BL_RX_LEN],
+}
+
+impl RxWithIrq {
+    /// Enable USART2 RXNE/IDLE/overrun interrupts. Must be called once,
+    /// before the corresponding `#[interrupt] fn USART2` can fire usefully.
+    pub fn new(usart2: &USART2) -> Self {
+        usart2.cr1.modify(|_, w| w.rxneie().set_bit().idleie().set_bit());
+        usart2.cr3.modify(|_, w| w.eie().set_bit());
+
+        RxWithIrq {
+            encoded: [0; BL_RX_LEN],
+            encoded_len: 0,
+            decoded: [0; BL_RX_LEN],
+        }
+    }
+
+    /// Number of bytes the ISR has queued but the main loop hasn't drained.
+    pub fn poll(&self) -> usize {
+        RX_RING.len()
+    }
+
+    /// Bytes dropped due to a ring-buffer overrun or a hardware USART overrun.
+    pub fn overrun_count(&self) -> u32 {
+        overrun_count()
+    }
+
+    /// USART framing errors reported by the peripheral.
+    pub fn framing_error_count(&self) -> u32 {
+        framing_error_count()
+    }
+
+    /// Drain queued bytes and return a decoded frame once a `0x00` COBS
+    /// delimiter has been seen. Returns `None` if no full frame is queued
+    /// yet; a malformed or oversized frame is dropped so the receiver
+    /// resyncs on the next delimiter.
+    pub fn try_read_frame(&mut self) -> Option<&[u8]> {
+        while let Some(byte) = RX_RING.pop() {
+            if byte == 0x00 {
+                if self.encoded_len > self.encoded.len() {
+                    // End of an oversized frame: drop it and start afresh.
+                    self.encoded_len = 0;
+                    continue;
+                }
+                let result = cobs_decode(&self.encoded[..self.encoded_len], &mut self.decoded);
+                self.encoded_len = 0;
+                return result.map(move |len| &self.decoded[..len]);
+            }
+
+            if self.encoded_len >= self.encoded.len() {
+                // Oversized frame: discard everything up to the next
+                // delimiter (an `encoded_len` past the buffer marks this).
+                self.encoded_len = self.encoded.len() + 1;
+                continue;
+            }
+
+            self.encoded[self.encoded_len] = byte;
+            self.encoded_len += 1;
+        }
+
+        None
+    }
+}
+
+/// USART2 interrupt handler: drains RXNE/overrun/framing status and feeds
+/// the ring buffer.
+#[interrupt]
+fn USART2() {
+    let usart2 = unsafe { &*USART2::ptr() };
+    let sr = usart2.sr.read();
+
+    if sr.ore().bit_is_set() {
+        OVERRUN_COUNT.fetch_add(1, Ordering::Relaxed);
+    }
+    if sr.fe().bit_is_set() {
+        FRAMING_ERROR_COUNT.fetch_add(1, Ordering::Relaxed);
+    }
+
+    if sr.rxne().bit_is_set() || sr.ore().bit_is_set() {
+        // Reading DR clears RXNE (and, combined with the SR read above,
+        // ORE/FE).
+        let byte = usart2.dr.read().dr().bits() as u8;
+        if !RX_RING.push(byte) {
+            OVERRUN_COUNT.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    if sr.idle().bit_is_set() {
+        let _ = usart2.dr.read();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ring_buffer_preserves_fifo_order() {
+        let ring = RingBuffer::new();
+        for byte in [0x01, 0x02, 0x03] {
+            assert!(ring.push(byte));
+        }
+        assert_eq!(ring.len(), 3);
+        assert_eq!(ring.pop(), Some(0x01));
+        assert_eq!(ring.pop(), Some(0x02));
+        assert_eq!(ring.pop(), Some(0x03));
+        assert_eq!(ring.pop(), None);
+    }
+
+    #[test]
+    fn ring_buffer_reports_full_instead_of_overwriting() {
+        let ring = RingBuffer::new();
+        for i in 0..RING_CAPACITY {
+            assert!(ring.push(i as u8));
+        }
+        assert!(!ring.push(0xFF));
+        assert_eq!(ring.len(), RING_CAPACITY);
+        assert_eq!(ring.pop(), Some(0));
+    }
+
+    #[test]
+    fn try_read_frame_waits_until_delimiter_then_decodes() {
+        let mut rx = RxWithIrq {
+            encoded: [0; BL_RX_LEN],
+            encoded_len: 0,
+            decoded: [0; BL_RX_LEN],
+        };
+
+        RX_RING.push(0x03);
+        RX_RING.push(0xAA);
+        assert!(rx.try_read_frame().is_none());
+
+        RX_RING.push(0xBB);
+        RX_RING.push(0x00);
+
+        let frame = rx.try_read_frame().expect("frame should be ready");
+        assert_eq!(frame, &[0xAA, 0xBB]);
+    }
+}
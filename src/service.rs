@@ -0,0 +1,234 @@
+// Structured telecommand/telemetry service layer
+//
+// Sits on top of `CommandPacket`, carried by the `BL_SERVICE_REQUEST`
+// command. Rather than a single ACK/NACK, every inbound telecommand (TC) is
+// routed by a service/subservice id pair and answered with one or more
+// typed telemetry (TM) reports - acceptance, start, and completion - each
+// echoing the TC's sequence count so a host can correlate every reply to
+// the command that produced it.
+use crate::constants::*;
+use crate::flash;
+use crate::handlers::CommandContext;
+use crate::uart::{Transport, TxHalf};
+
+/// A parsed telecommand: `[service][subservice][sequence_count LE u16][payload...]`.
+#[derive(Debug, Clone, Copy)]
+pub struct TcPacket<'a> {
+    pub service: u8,
+    pub subservice: u8,
+    pub sequence_count: u16,
+    pub payload: &'a [u8],
+}
+
+impl<'a> TcPacket<'a> {
+    pub fn parse(buffer: &'a [u8]) -> Option<Self> {
+        if buffer.len() < 4 {
+            return None;
+        }
+
+        Some(TcPacket {
+            service: buffer[0],
+            subservice: buffer[1],
+            sequence_count: u16::from_le_bytes([buffer[2], buffer[3]]),
+            payload: &buffer[4..],
+        })
+    }
+}
+
+/// Where a TM report sits in a command's acceptance/start/completion
+/// lifecycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TmKind {
+    Acceptance,
+    Start,
+    Completion,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TmStatus {
+    Success,
+    Failure,
+}
+
+/// A typed telemetry reply: `[type byte][service][subservice][sequence_count LE u16]`,
+/// followed by an action-specific payload (empty for ping).
+#[derive(Debug, Clone, Copy)]
+pub struct TmPacket {
+    pub kind: TmKind,
+    pub status: TmStatus,
+    pub service: u8,
+    pub subservice: u8,
+    pub sequence_count: u16,
+}
+
+impl TmPacket {
+    fn type_byte(&self) -> u8 {
+        match (self.kind, self.status) {
+            (TmKind::Acceptance, TmStatus::Success) => 0x01,
+            (TmKind::Acceptance, TmStatus::Failure) => 0x02,
+            (TmKind::Start, TmStatus::Success) => 0x03,
+            (TmKind::Start, TmStatus::Failure) => 0x04,
+            (TmKind::Completion, TmStatus::Success) => 0x05,
+            (TmKind::Completion, TmStatus::Failure) => 0x06,
+        }
+    }
+
+    /// Write the TM header through `serial`. Callers append any
+    /// action-specific payload bytes after this.
+    pub fn write(&self, tx: &TxHalf, serial: &mut impl Transport) {
+        tx.write_byte(self.type_byte(), serial);
+        tx.write_byte(self.service, serial);
+        tx.write_byte(self.subservice, serial);
+        tx.write_buffer(&self.sequence_count.to_le_bytes(), serial);
+    }
+}
+
+fn report(kind: TmKind, status: TmStatus, tc: &TcPacket, tx: &TxHalf, serial: &mut impl Transport) {
+    TmPacket {
+        kind,
+        status,
+        service: tc.service,
+        subservice: tc.subservice,
+        sequence_count: tc.sequence_count,
+    }
+    .write(tx, serial);
+}
+
+/// Ping service (service 1, subservice 1): accepts unconditionally and
+/// completes with an empty payload, so a host can verify the link before
+/// attempting anything destructive.
+fn handle_ping(tc: &TcPacket, tx: &TxHalf, serial: &mut impl Transport) {
+    report(TmKind::Acceptance, TmStatus::Success, tc, tx, serial);
+    report(TmKind::Completion, TmStatus::Success, tc, tx, serial);
+}
+
+/// Memory service (service 2), raw-write action (subservice 1). Payload:
+/// `[memory id][address LE u32][data...]`. Only `MEM_ID_FLASH` is
+/// supported; anything else, or a payload too short to hold a memory id and
+/// address, is rejected at the acceptance stage.
+fn handle_memory_raw_write(
+    tc: &TcPacket,
+    ctx: &mut CommandContext,
+    tx: &TxHalf,
+    serial: &mut impl Transport,
+) {
+    if tc.payload.len() < 5 || tc.payload[0] != MEM_ID_FLASH {
+        report(TmKind::Acceptance, TmStatus::Failure, tc, tx, serial);
+        return;
+    }
+
+    let address = u32::from_le_bytes([tc.payload[1], tc.payload[2], tc.payload[3], tc.payload[4]]);
+    let data = &tc.payload[5..];
+
+    if crate::memory::verify_address(address) != ADDR_VALID || !crate::memory::is_write_target_allowed(address) {
+        report(TmKind::Acceptance, TmStatus::Failure, tc, tx, serial);
+        return;
+    }
+
+    report(TmKind::Acceptance, TmStatus::Success, tc, tx, serial);
+    report(TmKind::Start, TmStatus::Success, tc, tx, serial);
+
+    let status = match flash::execute_mem_write(ctx.flash, address, data) {
+        Ok(()) => TmStatus::Success,
+        Err(_) => TmStatus::Failure,
+    };
+    report(TmKind::Completion, status, tc, tx, serial);
+}
+
+/// Diagnostics service (service 3), RX error counters (subservice 1). No
+/// payload. Completes with `[overrun_count LE u32][framing_error_count LE u32]`
+/// so a host can detect bytes the ISR had to drop without a way to observe
+/// that otherwise.
+fn handle_get_rx_error_counts(tc: &TcPacket, tx: &TxHalf, serial: &mut impl Transport) {
+    report(TmKind::Acceptance, TmStatus::Success, tc, tx, serial);
+    report(TmKind::Completion, TmStatus::Success, tc, tx, serial);
+    tx.write_buffer(&crate::rx_irq::overrun_count().to_le_bytes(), serial);
+    tx.write_buffer(&crate::rx_irq::framing_error_count().to_le_bytes(), serial);
+}
+
+/// Route a parsed telecommand to its service/subservice handler. An
+/// unrecognized service or subservice is rejected with an acceptance
+/// failure, same as a malformed payload for a known one.
+pub fn dispatch(tc: &TcPacket, ctx: &mut CommandContext, tx: &TxHalf, serial: &mut impl Transport) {
+    match (tc.service, tc.subservice) {
+        (SVC_PING, SVC_PING_SUB_PING) => handle_ping(tc, tx, serial),
+        (SVC_MEMORY, SVC_MEMORY_SUB_RAW_WRITE) => handle_memory_raw_write(tc, ctx, tx, serial),
+        (SVC_DIAGNOSTICS, SVC_DIAGNOSTICS_SUB_RX_ERRORS) => handle_get_rx_error_counts(tc, tx, serial),
+        _ => report(TmKind::Acceptance, TmStatus::Failure, tc, tx, serial),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::uart::MockTransport;
+
+    #[test]
+    fn tc_parse_rejects_short_buffer() {
+        assert!(TcPacket::parse(&[1, 2, 3]).is_none());
+    }
+
+    #[test]
+    fn tc_parse_recovers_fields() {
+        let tc = TcPacket::parse(&[SVC_PING, SVC_PING_SUB_PING, 0x34, 0x12, 0xAA]).unwrap();
+        assert_eq!(tc.service, SVC_PING);
+        assert_eq!(tc.subservice, SVC_PING_SUB_PING);
+        assert_eq!(tc.sequence_count, 0x1234);
+        assert_eq!(tc.payload, &[0xAA]);
+    }
+
+    #[test]
+    fn ping_replies_with_acceptance_then_empty_completion() {
+        let tc = TcPacket {
+            service: SVC_PING,
+            subservice: SVC_PING_SUB_PING,
+            sequence_count: 7,
+            payload: &[],
+        };
+        let tx = TxHalf::new();
+        let mut transport = MockTransport::default();
+
+        handle_ping(&tc, &tx, &mut transport);
+
+        assert_eq!(
+            transport.written,
+            std::vec![0x01, SVC_PING, SVC_PING_SUB_PING, 7, 0, 0x05, SVC_PING, SVC_PING_SUB_PING, 7, 0]
+        );
+    }
+
+    #[test]
+    fn diagnostics_rx_errors_replies_with_counters_after_completion() {
+        let tc = TcPacket {
+            service: SVC_DIAGNOSTICS,
+            subservice: SVC_DIAGNOSTICS_SUB_RX_ERRORS,
+            sequence_count: 3,
+            payload: &[],
+        };
+        let tx = TxHalf::new();
+        let mut transport = MockTransport::default();
+
+        handle_get_rx_error_counts(&tc, &tx, &mut transport);
+
+        let mut expected = std::vec![0x01, SVC_DIAGNOSTICS, SVC_DIAGNOSTICS_SUB_RX_ERRORS, 3, 0];
+        expected.extend_from_slice(&std::vec![0x05, SVC_DIAGNOSTICS, SVC_DIAGNOSTICS_SUB_RX_ERRORS, 3, 0]);
+        expected.extend_from_slice(&crate::rx_irq::overrun_count().to_le_bytes());
+        expected.extend_from_slice(&crate::rx_irq::framing_error_count().to_le_bytes());
+        assert_eq!(transport.written, expected);
+    }
+
+    #[test]
+    fn acceptance_failure_report_echoes_tc_header() {
+        let tc = TcPacket {
+            service: 0xFF,
+            subservice: 0xFF,
+            sequence_count: 1,
+            payload: &[],
+        };
+        let tx = TxHalf::new();
+        let mut transport = MockTransport::default();
+
+        report(TmKind::Acceptance, TmStatus::Failure, &tc, &tx, &mut transport);
+
+        assert_eq!(transport.written, std::vec![0x02, 0xFF, 0xFF, 1, 0]);
+    }
+}
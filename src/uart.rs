@@ -6,20 +6,227 @@ use stm32f4xx_hal::prelude::*;
 use stm32f4xx_hal::stm32::USART2;
 use crate::constants::*;
 
-/// UART communication wrapper
-pub struct UartComm {
-    rx_buffer: [u8; BL_RX_LEN],
-    rx_count: usize,
+/// Abstraction over ACK/NACK/byte I/O so command handlers can be exercised
+/// off-target against a mock sink instead of a real UART peripheral.
+pub trait Transport {
+    fn write_byte(&mut self, byte: u8);
+
+    fn write_buffer(&mut self, buffer: &[u8]) {
+        for &byte in buffer {
+            self.write_byte(byte);
+        }
+    }
+
+    fn send_ack(&mut self, command_code: u8, follow_len: u8) {
+        self.write_byte(BL_ACK);
+        self.write_byte(command_code);
+        self.write_byte(follow_len);
+    }
+
+    fn send_nack(&mut self) {
+        self.write_byte(BL_NACK);
+    }
 }
 
-impl UartComm {
-    pub fn new() -> Self {
-        UartComm {
-            rx_buffer: [0; BL_RX_LEN],
-            rx_count: 0,
+impl<W: embedded_hal::serial::Write<u8>> Transport for W {
+    fn write_byte(&mut self, byte: u8) {
+        nb::block!(self.write(byte)).ok();
+    }
+}
+
+/// `Transport` implementation backed by a plain byte buffer, used in tests.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct MockTransport {
+    pub written: std::vec::Vec<u8>,
+}
+
+#[cfg(test)]
+impl Transport for MockTransport {
+    fn write_byte(&mut self, byte: u8) {
+        self.written.push(byte);
+    }
+}
+
+/// Encode `input` as a COBS frame into `output`, including the trailing
+/// `0x00` delimiter. The body never contains a zero byte, so a receiver can
+/// always resynchronize on the next `0x00` in the stream.
+///
+/// Returns the number of bytes written to `output`, or `None` if `output` is
+/// too small.
+pub fn cobs_encode(input: &[u8], output: &mut [u8]) -> Option<usize> {
+    let mut out_idx = 1;
+    let mut code_idx = 0;
+    let mut code = 1u8;
+
+    if output.is_empty() {
+        return None;
+    }
+
+    for &byte in input {
+        if out_idx >= output.len() {
+            return None;
+        }
+
+        if byte == 0 {
+            output[code_idx] = code;
+            code = 1;
+            code_idx = out_idx;
+            out_idx += 1;
+        } else {
+            output[out_idx] = byte;
+            out_idx += 1;
+            code += 1;
+
+            if code == 0xFF {
+                if out_idx >= output.len() {
+                    return None;
+                }
+                output[code_idx] = code;
+                code = 1;
+                code_idx = out_idx;
+                out_idx += 1;
+            }
         }
     }
-    
+
+    output[code_idx] = code;
+
+    if out_idx >= output.len() {
+        return None;
+    }
+    output[out_idx] = 0;
+    out_idx += 1;
+
+    Some(out_idx)
+}
+
+/// Decode a COBS-encoded frame body (without the trailing `0x00` delimiter)
+/// from `input` into `output`.
+///
+/// Returns the number of decoded bytes, or `None` on a malformed frame (a
+/// zero byte where a code byte was expected, or a truncated block).
+pub fn cobs_decode(input: &[u8], output: &mut [u8]) -> Option<usize> {
+    let mut in_idx = 0;
+    let mut out_idx = 0;
+
+    while in_idx < input.len() {
+        let code = input[in_idx];
+        if code == 0 {
+            return None;
+        }
+        in_idx += 1;
+
+        for _ in 1..code {
+            if in_idx >= input.len() || out_idx >= output.len() {
+                return None;
+            }
+            output[out_idx] = input[in_idx];
+            out_idx += 1;
+            in_idx += 1;
+        }
+
+        if code != 0xFF && in_idx < input.len() {
+            if out_idx >= output.len() {
+                return None;
+            }
+            output[out_idx] = 0;
+            out_idx += 1;
+        }
+    }
+
+    Some(out_idx)
+}
+
+/// Parity mode for the negotiated UART line, applied via `apply_line_config`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+/// Negotiated UART line settings: word length, parity, and baud rate.
+///
+/// `word_length_bits` selects the STM32F4 USART's M bit directly (8 or
+/// 9-bit frame) - there is no 7-bit frame on this peripheral. `is_valid`
+/// accepts an 8-bit frame with any parity, and a 9-bit frame only with no
+/// parity.
+#[derive(Debug, Clone, Copy)]
+pub struct LineConfig {
+    pub word_length_bits: u8,
+    pub parity: Parity,
+    pub baud_rate: u32,
+}
+
+impl LineConfig {
+    pub const fn default_config() -> Self {
+        LineConfig {
+            word_length_bits: 8,
+            parity: Parity::None,
+            baud_rate: 115_200,
+        }
+    }
+
+    /// Whether this combination of word length and parity is accepted: an
+    /// 8-bit frame with any parity, or a 9-bit frame with no parity (M bit:
+    /// 8 or 9-bit frame; there is no 7-bit frame).
+    pub fn is_valid(&self) -> bool {
+        match self.word_length_bits {
+            8 => true,
+            9 => self.parity == Parity::None,
+            _ => false,
+        }
+    }
+}
+
+/// Reprogram USART2's CR1 (word length, parity) and BRR (baud rate) for a
+/// new `LineConfig`. Must only be called after the old-rate ACK has already
+/// been transmitted, since the new baud rate takes effect immediately.
+///
+/// The caller's ACK write only waits on TXE (data register empty), which
+/// frees up as soon as the byte moves into the shift register - not once it
+/// has actually gone out on the wire. Reprogramming BRR before that finishes
+/// would transmit the tail of the ACK at the new baud rate, so this also
+/// waits on TC (transmission complete) before touching CR1/BRR.
+pub fn apply_line_config(usart2: &USART2, pclk_hz: u32, config: &LineConfig) {
+    while usart2.sr.read().tc().bit_is_clear() {}
+
+    usart2.cr1.modify(|_, w| {
+        w.m().bit(config.word_length_bits == 9);
+        match config.parity {
+            Parity::None => w.pce().clear_bit(),
+            Parity::Even => w.pce().set_bit().ps().clear_bit(),
+            Parity::Odd => w.pce().set_bit().ps().set_bit(),
+        }
+    });
+
+    // USARTDIV = pclk / (16 * baud); BRR packs USARTDIV*16 directly as a
+    // mantissa (bits [15:4]) plus a 4-bit fraction (bits [3:0]) per the
+    // reference manual's oversampling-by-16 formula. Round the combined
+    // USARTDIV*16 value in one step rather than rounding the fraction on its
+    // own, so a fraction that rounds up to 16 carries into the mantissa
+    // instead of being masked away to 0.
+    let numerator = pclk_hz as u64 * 16;
+    let denominator = 16 * config.baud_rate as u64;
+    let usartdiv16 = (numerator + denominator / 2) / denominator;
+    let mantissa = (usartdiv16 >> 4) as u32;
+    let fraction = (usartdiv16 & 0xF) as u32;
+    usart2.brr.write(|w| unsafe { w.bits((mantissa << 4) | fraction) });
+}
+
+/// TX half of the UART link: stateless ACK/NACK/byte writes over any
+/// `Transport`. Owned independently of `RxHalf` so the main loop can send
+/// replies while an ISR (see `rx_irq::RxWithIrq`) exclusively owns receive
+/// state, with no `&mut` aliasing of a single combined object between them.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TxHalf;
+
+impl TxHalf {
+    pub fn new() -> Self {
+        TxHalf
+    }
+
     /// Read a single byte from UART
     pub fn read_byte<RX>(serial: &mut Serial<USART2, RX>) -> Option<u8>
     where
@@ -30,46 +237,118 @@ impl UartComm {
             Err(_) => None,
         }
     }
-    
-    /// Write a single byte to UART
-    pub fn write_byte(byte: u8, serial: &mut Serial<USART2, _, _>) {
-        nb::block!(serial.write(byte)).ok();
+
+    /// Write a single byte through the given transport
+    pub fn write_byte(&self, byte: u8, transport: &mut impl Transport) {
+        transport.write_byte(byte);
     }
-    
-    /// Write a buffer to UART
-    pub fn write_buffer(buffer: &[u8], serial: &mut Serial<USART2, _, _>) {
-        for &byte in buffer {
-            nb::block!(serial.write(byte)).ok();
-        }
+
+    /// Write a buffer through the given transport
+    pub fn write_buffer(&self, buffer: &[u8], transport: &mut impl Transport) {
+        transport.write_buffer(buffer);
     }
-    
+
     /// Send ACK response
-    pub fn send_ack(command_code: u8, follow_len: u8, serial: &mut Serial<USART2, _, _>) {
-        Self::write_byte(BL_ACK, serial);
-        Self::write_byte(command_code, serial);
-        Self::write_byte(follow_len, serial);
+    pub fn send_ack(&self, command_code: u8, follow_len: u8, transport: &mut impl Transport) {
+        transport.send_ack(command_code, follow_len);
     }
-    
+
     /// Send NACK response
-    pub fn send_nack(serial: &mut Serial<USART2, _, _>) {
-        Self::write_byte(BL_NACK, serial);
+    pub fn send_nack(&self, transport: &mut impl Transport) {
+        transport.send_nack();
     }
-    
+}
+
+/// RX half of the UART link: owns the COBS decode buffer for the blocking
+/// `read_frame` path. The interrupt-driven receive path used on-target
+/// lives separately in `rx_irq::RxWithIrq`; this half remains as the
+/// blocking fallback (e.g. a build without IRQ wiring, or driving the COBS
+/// decode path directly in a host test).
+pub struct RxHalf {
+    rx_buffer: [u8; BL_RX_LEN],
+    rx_count: usize,
+}
+
+impl RxHalf {
+    pub fn new() -> Self {
+        RxHalf {
+            rx_buffer: [0; BL_RX_LEN],
+            rx_count: 0,
+        }
+    }
+
     /// Get reference to RX buffer
     pub fn rx_buffer(&self) -> &[u8] {
         &self.rx_buffer
     }
-    
+
     /// Get mutable reference to RX buffer
     pub fn rx_buffer_mut(&mut self) -> &mut [u8] {
         &mut self.rx_buffer
     }
-    
+
     /// Clear RX buffer
     pub fn clear_rx_buffer(&mut self) {
         self.rx_buffer = [0; BL_RX_LEN];
         self.rx_count = 0;
     }
+
+    /// Read one COBS-delimited frame from `rx`, decode it into `rx_buffer`,
+    /// and return the decoded frame.
+    ///
+    /// Blocks until a `0x00` delimiter is seen. A dropped or spurious byte
+    /// mid-frame no longer desyncs the receiver permanently: the next
+    /// `0x00` always starts a fresh frame. Returns `None` on a UART read
+    /// error, an encoded frame too long for the receive buffer, or a
+    /// malformed COBS body.
+    pub fn read_frame<RX>(&mut self, rx: &mut RX) -> Option<&[u8]>
+    where
+        RX: Read<u8>,
+    {
+        let mut encoded = [0u8; BL_RX_LEN];
+        let mut encoded_len = 0;
+
+        loop {
+            let byte = nb::block!(rx.read()).ok()?;
+
+            if byte == 0x00 {
+                break;
+            }
+
+            if encoded_len >= encoded.len() {
+                return None;
+            }
+
+            encoded[encoded_len] = byte;
+            encoded_len += 1;
+        }
+
+        let decoded_len = cobs_decode(&encoded[..encoded_len], &mut self.rx_buffer)?;
+        self.rx_count = decoded_len;
+        Some(&self.rx_buffer[..decoded_len])
+    }
+}
+
+/// UART communication wrapper, kept as a convenience pairing of both
+/// halves. Call `split()` to separate ownership, the way
+/// `stm32f4xx_hal::serial::Serial::split()` separates its `Tx`/`Rx`.
+pub struct UartComm {
+    pub tx: TxHalf,
+    pub rx: RxHalf,
+}
+
+impl UartComm {
+    pub fn new() -> Self {
+        UartComm {
+            tx: TxHalf::new(),
+            rx: RxHalf::new(),
+        }
+    }
+
+    /// Split into independently-owned TX and RX halves.
+    pub fn split(self) -> (TxHalf, RxHalf) {
+        (self.tx, self.rx)
+    }
 }
 
 /// Parse command packet
@@ -90,10 +369,17 @@ impl CommandPacket {
         if buffer.len() < 4 {
             return None;
         }
-        
+
         let length = buffer[0];
         let command = buffer[1];
-        
+
+        // `length` must cover at least a command byte and the trailing
+        // CRC32 (1 + 4 = 5), or `payload_len - 4` below underflows on a
+        // short-but-otherwise-well-formed frame.
+        if length < 5 {
+            return None;
+        }
+
         if buffer.len() < (2 + length as usize) {
             return None;
         }
@@ -124,3 +410,113 @@ impl CommandPacket {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crc::{verify_frame_crc, SoftwareCrc};
+
+    fn framed(command: u8, payload: &[u8]) -> std::vec::Vec<u8> {
+        let mut body = std::vec![command];
+        body.extend_from_slice(payload);
+        let length = (body.len() + 4) as u8;
+
+        let mut frame = std::vec![length];
+        frame.extend_from_slice(&body);
+        // verify_frame_crc CRCs everything before the trailing CRC32,
+        // including the leading length byte - so the CRC has to cover
+        // `frame`, not just `body`.
+        let crc = crate::crc::calculate_crc(&frame);
+        frame.extend_from_slice(&crc.to_le_bytes());
+        frame
+    }
+
+    #[test]
+    fn parse_rejects_buffer_shorter_than_declared_length() {
+        let frame = framed(BL_GET_VER, &[0xAA]);
+        assert!(CommandPacket::parse(&frame[..frame.len() - 1]).is_none());
+    }
+
+    #[test]
+    fn parse_rejects_buffer_shorter_than_header() {
+        assert!(CommandPacket::parse(&[0x05]).is_none());
+    }
+
+    #[test]
+    fn parse_happy_path_recovers_command_and_payload() {
+        let frame = framed(BL_GO_TO_ADDR, &[0x01, 0x02, 0x03, 0x04]);
+        let packet = CommandPacket::parse(&frame).expect("frame should parse");
+
+        assert_eq!(packet.command, BL_GO_TO_ADDR);
+        assert_eq!(packet.payload_len, 4);
+        assert_eq!(&packet.payload[..packet.payload_len], &[0x01, 0x02, 0x03, 0x04]);
+    }
+
+    #[test]
+    fn verify_frame_crc_accepts_a_clean_frame() {
+        let frame = framed(BL_GET_VER, &[0xAA]);
+
+        let mut engine = SoftwareCrc;
+        assert!(verify_frame_crc(&frame, &mut engine));
+    }
+
+    #[test]
+    fn verify_frame_crc_rejects_corrupted_payload() {
+        let mut frame = framed(BL_GET_VER, &[0xAA]);
+        let last = frame.len() - 1;
+        frame[last] ^= 0xFF; // corrupt one CRC byte
+
+        let mut engine = SoftwareCrc;
+        assert!(!verify_frame_crc(&frame, &mut engine));
+    }
+
+    #[test]
+    fn cobs_round_trip_has_no_zero_bytes_in_body() {
+        let frame = framed(BL_MEM_WRITE, &[0x00, 0x01, 0x00, 0x00, 0xFF]);
+
+        let mut encoded = [0u8; BL_RX_LEN];
+        let encoded_len = cobs_encode(&frame, &mut encoded).expect("should encode");
+
+        // Body excludes the trailing 0x00 delimiter.
+        assert_eq!(encoded[encoded_len - 1], 0);
+        assert!(encoded[..encoded_len - 1].iter().all(|&b| b != 0));
+
+        let mut decoded = [0u8; BL_RX_LEN];
+        let decoded_len = cobs_decode(&encoded[..encoded_len - 1], &mut decoded).expect("should decode");
+
+        assert_eq!(&decoded[..decoded_len], &frame[..]);
+    }
+
+    #[test]
+    fn cobs_round_trip_handles_blocks_of_254_non_zero_bytes() {
+        let mut data = std::vec![0xABu8; 300];
+        data[260] = 0x00;
+
+        let mut encoded = [0u8; 512];
+        let encoded_len = cobs_encode(&data, &mut encoded).expect("should encode");
+        assert!(encoded[..encoded_len - 1].iter().all(|&b| b != 0));
+
+        let mut decoded = [0u8; 512];
+        let decoded_len = cobs_decode(&encoded[..encoded_len - 1], &mut decoded).expect("should decode");
+
+        assert_eq!(&decoded[..decoded_len], &data[..]);
+    }
+
+    #[test]
+    fn line_config_rejects_7bit_word_length() {
+        let config = LineConfig { word_length_bits: 7, parity: Parity::Even, baud_rate: 9600 };
+        assert!(!config.is_valid());
+    }
+
+    #[test]
+    fn line_config_9bit_rejects_parity() {
+        let config = LineConfig { word_length_bits: 9, parity: Parity::Odd, baud_rate: 9600 };
+        assert!(!config.is_valid());
+    }
+
+    #[test]
+    fn cobs_decode_rejects_unexpected_zero_code_byte() {
+        let mut decoded = [0u8; 8];
+        assert!(cobs_decode(&[0x02, 0xAA, 0x00], &mut decoded).is_none());
+    }
+}